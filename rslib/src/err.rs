@@ -0,0 +1,43 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum AnkiError {
+    IOError {
+        info: String,
+    },
+    /// A file was larger than `limit` bytes, the size media syncing
+    /// supports.
+    MediaTooLarge {
+        fname: String,
+        size: usize,
+        limit: usize,
+    },
+}
+
+impl fmt::Display for AnkiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AnkiError::IOError { info } => write!(f, "{}", info),
+            AnkiError::MediaTooLarge { fname, size, limit } => write!(
+                f,
+                "{} is {} bytes, over the media sync limit of {} bytes",
+                fname, size, limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AnkiError {}
+
+impl From<io::Error> for AnkiError {
+    fn from(err: io::Error) -> Self {
+        AnkiError::IOError {
+            info: err.to_string(),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, AnkiError>;
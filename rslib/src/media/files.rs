@@ -6,9 +6,12 @@ use lazy_static::lazy_static;
 use log::debug;
 use regex::Regex;
 use sha1::Sha1;
+use same_file::Handle;
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::io::Read;
 use std::path::Path;
+use std::sync::Arc;
 use std::{fs, io, time};
 use trash::remove_all;
 use unicode_normalization::{is_nfc, UnicodeNormalization};
@@ -86,6 +89,77 @@ pub(crate) fn normalize_filename(fname: &str) -> Cow<str> {
     output
 }
 
+/// Like [normalize_filename], but also corrects the extension based on the
+/// data's sniffed content type when the supplied one is missing or clearly
+/// wrong. Used by [add_data_to_folder_uniquely] and [add_file_from_ankiweb]
+/// so notes don't end up referencing files players and the webview can't
+/// render. Already-correct names are left untouched, preserving the
+/// `Cow::Borrowed` fast path.
+pub(crate) fn normalize_filename_with_data<'a>(fname: &'a str, data: &[u8]) -> Cow<'a, str> {
+    let normalized = normalize_filename(fname);
+    let kind = sniff_media_kind(data);
+    if kind == MediaKind::Unknown || extension_matches_kind(normalized.as_ref(), kind) {
+        return normalized;
+    }
+
+    let canonical_ext = known_extensions(kind)[0];
+    Cow::Owned(replace_extension(normalized.as_ref(), canonical_ext))
+}
+
+/// Swap a filename's extension for `ext`, treating everything before the
+/// last '.' as the stem (or the whole name, if there's no '.'). Run back
+/// through [truncate_filename] so the swap can't push the result over
+/// `MAX_FILENAME_LENGTH`, the same invariant `normalize_filename` enforces.
+fn replace_extension(fname: &str, ext: &str) -> String {
+    let stem = match fname.rfind('.') {
+        Some(idx) => &fname[..idx],
+        None => fname,
+    };
+
+    truncate_filename(&format!("{}.{}", stem, ext), MAX_FILENAME_LENGTH).into_owned()
+}
+
+/// Return an error if `data` is over the size media syncing supports, so
+/// oversized files are caught at add time rather than failing later at sync.
+fn check_media_size_limit(fname: &str, data: &[u8]) -> Result<()> {
+    if data.len() > MEDIA_SYNC_FILESIZE_LIMIT {
+        Err(AnkiError::MediaTooLarge {
+            fname: fname.to_string(),
+            size: data.len(),
+            limit: MEDIA_SYNC_FILESIZE_LIMIT,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// A file in the media folder that's already over the sync size limit.
+pub(super) struct OversizedFile {
+    pub fname: String,
+    pub size: u64,
+}
+
+/// Audit the media folder for files over the sync size limit, so users can
+/// find and shrink them proactively instead of discovering sync breakage
+/// after the fact.
+pub(super) fn files_exceeding_sync_limit(media_folder: &Path) -> Result<Vec<OversizedFile>> {
+    let mut oversized = vec![];
+    for entry in fs::read_dir(media_folder)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let size = entry.metadata()?.len();
+        if size as usize > MEDIA_SYNC_FILESIZE_LIMIT {
+            oversized.push(OversizedFile {
+                fname: entry.file_name().to_string_lossy().into_owned(),
+                size,
+            });
+        }
+    }
+    Ok(oversized)
+}
+
 /// Write desired_name into folder, renaming if existing file has different content.
 /// Returns the used filename.
 pub fn add_data_to_folder_uniquely<'a, P>(
@@ -93,13 +167,33 @@ pub fn add_data_to_folder_uniquely<'a, P>(
     desired_name: &'a str,
     data: &[u8],
     sha1: [u8; 20],
-) -> io::Result<Cow<'a, str>>
+) -> Result<Cow<'a, str>>
 where
     P: AsRef<Path>,
 {
-    let normalized_name = normalize_filename(desired_name);
+    check_media_size_limit(desired_name, data)?;
+    Ok(add_data_to_folder_uniquely_inner(
+        folder.as_ref(),
+        desired_name,
+        data,
+        sha1,
+    )?)
+}
+
+/// The actual unique-write logic shared by [add_data_to_folder_uniquely] and
+/// [import_media_from_tar]. Deliberately does not enforce
+/// `MEDIA_SYNC_FILESIZE_LIMIT`: that limit is about AnkiWeb sync eligibility,
+/// not local storage, and a tar restore should bring back every file in the
+/// archive regardless of whether it would later be rejected by sync.
+fn add_data_to_folder_uniquely_inner<'a>(
+    folder: &Path,
+    desired_name: &'a str,
+    data: &[u8],
+    sha1: [u8; 20],
+) -> io::Result<Cow<'a, str>> {
+    let normalized_name = normalize_filename_with_data(desired_name, data);
 
-    let mut target_path = folder.as_ref().join(normalized_name.as_ref());
+    let mut target_path = folder.join(normalized_name.as_ref());
 
     let existing_file_hash = existing_file_sha1(&target_path)?;
     if existing_file_hash.is_none() {
@@ -267,8 +361,10 @@ pub(super) fn add_file_from_ankiweb(
     fname: &str,
     data: &[u8],
 ) -> Result<AddedFile> {
+    check_media_size_limit(fname, data)?;
+
     let sha1 = sha1_of_data(data);
-    let normalized = normalize_filename(fname);
+    let normalized = normalize_filename_with_data(fname, data);
 
     // if the filename is already valid, we can write the file directly
     let (renamed_from, path) = if let Cow::Borrowed(_) = normalized {
@@ -295,6 +391,509 @@ pub(super) fn add_file_from_ankiweb(
     })
 }
 
+/// Size in bytes of a tar header or data block; all tar records are padded
+/// up to a multiple of this.
+const TAR_BLOCK_SIZE: usize = 512;
+
+/// Upper bound on a single tar entry's declared size. Guards against a
+/// corrupted or truncated archive header causing us to attempt an enormous
+/// allocation before we've even confirmed the bytes are there; generous
+/// enough for legitimate media that's over the (much smaller) sync limit.
+const MAX_TAR_ENTRY_SIZE: usize = MEDIA_SYNC_FILESIZE_LIMIT * 10;
+
+/// Write every file in the media folder into `writer` as a tar archive, so it
+/// can be used as a backup or transferred independently of AnkiWeb sync.
+///
+/// The archive is written incrementally, so the whole collection never needs
+/// to sit in memory at once.
+pub(super) fn export_media_to_tar<W: io::Write>(media_folder: &Path, writer: &mut W) -> Result<()> {
+    for entry in fs::read_dir(media_folder)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let fname = entry.file_name().to_string_lossy().into_owned();
+        let data = fs::read(entry.path())?;
+        let mtime = mtime_as_i64(entry.path())?;
+        write_tar_entry(writer, &fname, &data, mtime)?;
+    }
+    // the archive is terminated by two zeroed blocks
+    writer.write_all(&[0; TAR_BLOCK_SIZE * 2])?;
+    Ok(())
+}
+
+/// Extract a tar archive produced by [export_media_to_tar] back into the
+/// media folder. Each name is passed through [normalize_filename] and the
+/// same unique-write machinery [add_data_to_folder_uniquely] uses, so
+/// restored files land safely even if the archive was produced on a
+/// different platform. Unlike [add_data_to_folder_uniquely], restore does
+/// not enforce `MEDIA_SYNC_FILESIZE_LIMIT` — that limit is about AnkiWeb
+/// sync eligibility, and this backup format is independent of sync.
+pub(super) fn import_media_from_tar<R: io::Read>(media_folder: &Path, reader: &mut R) -> Result<()> {
+    let mut pending_name: Option<String> = None;
+    loop {
+        let mut header = [0; TAR_BLOCK_SIZE];
+        if reader.read_exact(&mut header).is_err() {
+            // truncated or missing end-of-archive marker; treat as the end
+            break;
+        }
+        if header.iter().all(|&b| b == 0) {
+            // end-of-archive marker
+            break;
+        }
+
+        let size = read_tar_octal(&header[124..136]) as usize;
+        let typeflag = header[156];
+        let data = read_tar_block_data(reader, size)?;
+
+        if typeflag == b'x' {
+            // PAX extended header; the following entry's real name is in here
+            pending_name = Some(parse_pax_path_record(&data));
+            continue;
+        }
+
+        let fname = pending_name.take().unwrap_or_else(|| read_tar_name(&header));
+        let normalized = normalize_filename(&fname);
+        let sha1 = sha1_of_data(&data);
+        add_data_to_folder_uniquely_inner(media_folder, normalized.as_ref(), &data, sha1)?;
+    }
+    Ok(())
+}
+
+/// Write a single file as a ustar entry, prefixing it with a PAX extended
+/// header when `name` isn't ASCII or doesn't fit in ustar's 100-byte `name`
+/// field.
+fn write_tar_entry<W: io::Write>(writer: &mut W, name: &str, data: &[u8], mtime: i64) -> Result<()> {
+    let ustar_name = if name.is_ascii() && name.len() <= 100 {
+        name
+    } else {
+        write_pax_extended_header(writer, name)?;
+        truncate_to_char_boundary(name, 100)
+    };
+
+    writer.write_all(&ustar_header(ustar_name, data.len() as u64, mtime, b'0'))?;
+    writer.write_all(data)?;
+    write_tar_padding(writer, data.len())?;
+    Ok(())
+}
+
+/// Emit a PAX extended-header record carrying the real path, for names that
+/// aren't ASCII or don't fit ustar's 100-byte `name` field
+/// (`MAX_FILENAME_LENGTH` is 120).
+fn write_pax_extended_header<W: io::Write>(writer: &mut W, name: &str) -> Result<()> {
+    let record = pax_path_record(name);
+    writer.write_all(&ustar_header("pax_header", record.len() as u64, 0, b'x'))?;
+    writer.write_all(record.as_bytes())?;
+    write_tar_padding(writer, record.len())?;
+    Ok(())
+}
+
+/// Build a `"<len> path=<value>\n"` PAX record. `<len>` is the total byte
+/// length of the record, including the digits of `<len>` itself, so we solve
+/// the self-reference by incrementing the guess until it stabilizes.
+fn pax_path_record(value: &str) -> String {
+    let suffix = format!(" path={}\n", value);
+    let mut len = suffix.len();
+    loop {
+        let candidate = len.to_string().len() + suffix.len();
+        if candidate == len {
+            return format!("{}{}", len, suffix);
+        }
+        len = candidate;
+    }
+}
+
+/// Build a 512-byte ustar header with a checksum computed over its own bytes.
+fn ustar_header(name: &str, size: u64, mtime: i64, typeflag: u8) -> [u8; TAR_BLOCK_SIZE] {
+    let mut header = [0; TAR_BLOCK_SIZE];
+    write_tar_str(&mut header[0..100], name);
+    write_tar_octal(&mut header[100..108], 0o644);
+    write_tar_octal(&mut header[124..136], size);
+    write_tar_octal(&mut header[136..148], mtime.max(0) as u64);
+    // checksum field reads as spaces while the checksum itself is computed
+    header[148..156].copy_from_slice(b"        ");
+    header[156] = typeflag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    write_tar_checksum(&mut header[148..156], checksum);
+
+    header
+}
+
+fn write_tar_str(field: &mut [u8], value: &str) {
+    let bytes = value.as_bytes();
+    let len = bytes.len().min(field.len());
+    field[..len].copy_from_slice(&bytes[..len]);
+}
+
+fn write_tar_octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let octal = format!("{:0width$o}", value, width = width);
+    let bytes = octal.as_bytes();
+    field[..width].copy_from_slice(&bytes[bytes.len() - width..]);
+    field[width] = 0;
+}
+
+fn write_tar_checksum(field: &mut [u8], checksum: u32) {
+    field.copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+}
+
+fn write_tar_padding<W: io::Write>(writer: &mut W, data_len: usize) -> Result<()> {
+    let padding = (TAR_BLOCK_SIZE - (data_len % TAR_BLOCK_SIZE)) % TAR_BLOCK_SIZE;
+    if padding > 0 {
+        writer.write_all(&vec![0; padding])?;
+    }
+    Ok(())
+}
+
+fn read_tar_block_data<R: io::Read>(reader: &mut R, size: usize) -> Result<Vec<u8>> {
+    if size > MAX_TAR_ENTRY_SIZE {
+        return Err(AnkiError::IOError {
+            info: format!(
+                "tar entry size {} exceeds the maximum of {}",
+                size, MAX_TAR_ENTRY_SIZE
+            ),
+        });
+    }
+
+    let mut data = vec![0; size];
+    reader.read_exact(&mut data)?;
+    let padding = (TAR_BLOCK_SIZE - (size % TAR_BLOCK_SIZE)) % TAR_BLOCK_SIZE;
+    if padding > 0 {
+        let mut pad = vec![0; padding];
+        reader.read_exact(&mut pad)?;
+    }
+    Ok(data)
+}
+
+fn read_tar_octal(field: &[u8]) -> u64 {
+    let text = String::from_utf8_lossy(field);
+    u64::from_str_radix(text.trim_end_matches('\0').trim(), 8).unwrap_or(0)
+}
+
+fn read_tar_name(header: &[u8; TAR_BLOCK_SIZE]) -> String {
+    let end = header[0..100].iter().position(|&b| b == 0).unwrap_or(100);
+    String::from_utf8_lossy(&header[0..end]).into_owned()
+}
+
+fn parse_pax_path_record(data: &[u8]) -> String {
+    String::from_utf8_lossy(data)
+        .splitn(2, "path=")
+        .nth(1)
+        .map(|s| s.trim_end_matches('\n').to_string())
+        .unwrap_or_default()
+}
+
+/// Scan the media folder for byte-identical files, so users can reclaim
+/// space from re-imported assets. Files are first bucketed by size, since
+/// duplicates must share a size, before the comparatively expensive SHA1 is
+/// computed, so large collections without duplicates stay fast.
+pub(super) fn find_duplicate_media(media_folder: &Path) -> Result<Vec<Vec<String>>> {
+    let mut by_size: HashMap<u64, Vec<String>> = HashMap::new();
+    for entry in fs::read_dir(media_folder)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let size = entry.metadata()?.len();
+        let fname = entry.file_name().to_string_lossy().into_owned();
+        by_size.entry(size).or_default().push(fname);
+    }
+
+    let mut clusters = vec![];
+    for fnames in by_size.into_values() {
+        if fnames.len() < 2 {
+            // no other file shares this size, so it can't have a duplicate
+            continue;
+        }
+
+        let mut by_hash: HashMap<[u8; 20], Vec<String>> = HashMap::new();
+        for fname in fnames {
+            let hash = sha1_of_file(&media_folder.join(&fname))?;
+            by_hash.entry(hash).or_default().push(fname);
+        }
+
+        clusters.extend(by_hash.into_values().filter(|group| group.len() > 1));
+    }
+
+    Ok(clusters)
+}
+
+/// Replace duplicate files with hardlinks to a single canonical copy,
+/// reclaiming disk space. Files already hardlinked to the canonical copy are
+/// left alone, and duplicates that can't be hardlinked (eg because they live
+/// on a different filesystem) are left as standalone copies.
+pub(super) fn deduplicate_media(media_folder: &Path) -> Result<()> {
+    for cluster in find_duplicate_media(media_folder)? {
+        let mut fnames = cluster.into_iter();
+        let canonical = match fnames.next() {
+            Some(fname) => fname,
+            None => continue,
+        };
+        let canonical_path = media_folder.join(&canonical);
+        let canonical_identity = file_identity(&canonical_path)?;
+
+        for fname in fnames {
+            let path = media_folder.join(&fname);
+            if file_identity(&path)? == canonical_identity {
+                // already hardlinked to the canonical file
+                continue;
+            }
+
+            // link (or copy) to a temp name first, then atomically rename it
+            // over the target, so the target path is never left absent if
+            // something goes wrong partway through
+            let tmp_path = media_folder.join(format!(".{}.dedup-tmp", fname));
+            if fs::hard_link(&canonical_path, &tmp_path).is_err() {
+                // cross-device link; fall back to a copy in place
+                fs::write(&tmp_path, fs::read(&canonical_path)?)?;
+            }
+            fs::rename(&tmp_path, &path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Return a handle identifying the file a path resolves to on disk, so two
+/// paths can be compared to see if they're already hardlinked together. Uses
+/// `st_dev`/`st_ino` on Unix and the file index/volume id on Windows.
+fn file_identity(path: &Path) -> io::Result<Handle> {
+    Handle::from_path(path)
+}
+
+/// The file type we sniffed from a media file's leading bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum MediaKind {
+    Jpeg,
+    Png,
+    Gif,
+    Webp,
+    Svg,
+    Mp3,
+    Ogg,
+    Flac,
+    Wav,
+    Mp4,
+    Webm,
+    Unknown,
+}
+
+/// What's wrong with a media file found by [check_media_integrity].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum MediaProblem {
+    /// The file is shorter than its format's trailer/structure requires.
+    Truncated,
+    /// The sniffed content type doesn't match the file's extension.
+    MismatchedExtension,
+}
+
+pub(super) struct MediaIntegrityProblem {
+    pub fname: String,
+    pub detected_kind: MediaKind,
+    pub problem: MediaProblem,
+}
+
+/// Scan the media folder for corrupt or mislabelled files, so a caller can
+/// surface a cleanup report. Nothing is deleted automatically.
+pub(super) fn check_media_integrity(media_folder: &Path) -> Result<Vec<MediaIntegrityProblem>> {
+    let mut problems = vec![];
+    for entry in fs::read_dir(media_folder)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let fname = entry.file_name().to_string_lossy().into_owned();
+        let data = fs::read(entry.path())?;
+        let kind = sniff_media_kind(&data);
+
+        if let Some(problem) = structural_problem(kind, &data) {
+            problems.push(MediaIntegrityProblem {
+                fname,
+                detected_kind: kind,
+                problem,
+            });
+            continue;
+        }
+
+        if kind != MediaKind::Unknown && !extension_matches_kind(&fname, kind) {
+            problems.push(MediaIntegrityProblem {
+                fname,
+                detected_kind: kind,
+                problem: MediaProblem::MismatchedExtension,
+            });
+        }
+    }
+    Ok(problems)
+}
+
+/// Sniff the real file type from its leading magic bytes.
+fn sniff_media_kind(data: &[u8]) -> MediaKind {
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        MediaKind::Jpeg
+    } else if data.starts_with(&[0x89, 0x50, 0x4E, 0x47]) {
+        MediaKind::Png
+    } else if data.starts_with(&[0x47, 0x49, 0x46, 0x38]) {
+        MediaKind::Gif
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP".as_ref()) {
+        MediaKind::Webp
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WAVE".as_ref()) {
+        MediaKind::Wav
+    } else if looks_like_svg(data) {
+        MediaKind::Svg
+    } else if data.starts_with(b"ID3") || data.starts_with(&[0xFF, 0xFB]) {
+        MediaKind::Mp3
+    } else if data.starts_with(&[0x4F, 0x67, 0x67, 0x53]) {
+        MediaKind::Ogg
+    } else if data.starts_with(b"fLaC") {
+        MediaKind::Flac
+    } else if data.get(4..8) == Some(b"ftyp".as_ref()) {
+        MediaKind::Mp4
+    } else if data.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        MediaKind::Webm
+    } else {
+        MediaKind::Unknown
+    }
+}
+
+/// SVGs are plain XML, so we sniff them by looking for the expected prolog
+/// or root tag rather than a fixed magic number.
+fn looks_like_svg(data: &[u8]) -> bool {
+    let head = &data[..data.len().min(256)];
+    let text = String::from_utf8_lossy(head);
+    let trimmed = text.trim_start_matches('\u{feff}').trim_start();
+    trimmed.starts_with("<?xml") || trimmed.starts_with("<svg")
+}
+
+/// Cheaply validate the structural invariants of a sniffed file, without
+/// doing a full parse.
+fn structural_problem(kind: MediaKind, data: &[u8]) -> Option<MediaProblem> {
+    let truncated = match kind {
+        MediaKind::Jpeg => !data.ends_with(&[0xFF, 0xD9]),
+        MediaKind::Png => !contains_subslice(data, b"IEND"),
+        MediaKind::Gif => data.last() != Some(&0x3B),
+        MediaKind::Ogg => ogg_is_truncated(data),
+        MediaKind::Webm => webm_is_truncated(data),
+        MediaKind::Webp
+        | MediaKind::Svg
+        | MediaKind::Mp3
+        | MediaKind::Flac
+        | MediaKind::Wav
+        | MediaKind::Mp4
+        | MediaKind::Unknown => false,
+    };
+
+    if truncated {
+        Some(MediaProblem::Truncated)
+    } else {
+        None
+    }
+}
+
+fn contains_subslice(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+/// Walk an OGG bitstream page by page, checking each page's segment table
+/// accounts for all of its declared payload, and that the last page reaches
+/// the end of the file.
+fn ogg_is_truncated(data: &[u8]) -> bool {
+    let mut offset = 0;
+    while offset + 27 <= data.len() {
+        if &data[offset..offset + 4] != b"OggS" {
+            break;
+        }
+        let segment_count = data[offset + 26] as usize;
+        if offset + 27 + segment_count > data.len() {
+            return true;
+        }
+        let payload_len: usize = data[offset + 27..offset + 27 + segment_count]
+            .iter()
+            .map(|&len| len as usize)
+            .sum();
+        let page_len = 27 + segment_count + payload_len;
+        if offset + page_len > data.len() {
+            return true;
+        }
+        offset += page_len;
+    }
+    offset != data.len()
+}
+
+/// Walk a WebM/EBML bitstream element by element at the top level, checking
+/// each element's declared size is accounted for, so a file cut off
+/// mid-element is reported as truncated.
+fn webm_is_truncated(data: &[u8]) -> bool {
+    let mut offset = 0;
+    while offset < data.len() {
+        let id_len = match data.get(offset).and_then(|&b| ebml_vint_length(b)) {
+            Some(len) if offset + len <= data.len() => len,
+            _ => return true,
+        };
+
+        let size_offset = offset + id_len;
+        let size_len = match data.get(size_offset).and_then(|&b| ebml_vint_length(b)) {
+            Some(len) => len,
+            None => return true,
+        };
+        if size_offset + size_len > data.len() {
+            return true;
+        }
+        let mut size_value = (data[size_offset] & (0xFF >> size_len)) as u64;
+        for &byte in &data[size_offset + 1..size_offset + size_len] {
+            size_value = (size_value << 8) | byte as u64;
+        }
+
+        // an element whose size vint is all 1s has "unknown size" and runs
+        // to the end of its parent; we can't validate further without a
+        // full parse, so treat the file as fine from here
+        if size_value == (1u64 << (7 * size_len)) - 1 {
+            return false;
+        }
+
+        let element_len = id_len + size_len + size_value as usize;
+        if offset + element_len > data.len() {
+            return true;
+        }
+        offset += element_len;
+    }
+    offset != data.len()
+}
+
+/// The number of bytes an EBML variable-length integer occupies, found from
+/// the position of the leading 1 bit in its first byte.
+fn ebml_vint_length(first_byte: u8) -> Option<usize> {
+    (1..=8).find(|len| first_byte & (0x80 >> (len - 1)) != 0)
+}
+
+/// The extensions accepted for a given sniffed kind. The first entry is the
+/// canonical one used when renaming a mislabelled file.
+fn known_extensions(kind: MediaKind) -> &'static [&'static str] {
+    match kind {
+        MediaKind::Jpeg => &["jpg", "jpeg"],
+        MediaKind::Png => &["png"],
+        MediaKind::Gif => &["gif"],
+        MediaKind::Webp => &["webp"],
+        MediaKind::Svg => &["svg"],
+        MediaKind::Mp3 => &["mp3"],
+        MediaKind::Ogg => &["ogg", "oga", "ogv"],
+        MediaKind::Flac => &["flac"],
+        MediaKind::Wav => &["wav"],
+        MediaKind::Mp4 => &["mp4", "m4a"],
+        MediaKind::Webm => &["webm"],
+        MediaKind::Unknown => &[],
+    }
+}
+
+fn extension_matches_kind(fname: &str, kind: MediaKind) -> bool {
+    let ext = Path::new(fname)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    known_extensions(kind).contains(&ext.as_str())
+}
+
 pub(super) fn data_for_file(media_folder: &Path, fname: &str) -> Result<Option<Vec<u8>>> {
     let mut file = match fs::File::open(&media_folder.join(fname)) {
         Ok(file) => file,
@@ -311,13 +910,171 @@ pub(super) fn data_for_file(media_folder: &Path, fname: &str) -> Result<Option<V
     Ok(Some(buf))
 }
 
+/// Async counterpart to [sha1_of_file], streaming the file in 64 KiB chunks
+/// so the sync engine can hash many files concurrently instead of blocking a
+/// thread per file.
+pub(super) async fn sha1_of_file_async(path: &Path) -> io::Result<[u8; 20]> {
+    use tokio::io::AsyncReadExt;
+
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha1::new();
+    let mut buf = [0; 64 * 1024];
+    loop {
+        match file.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => hasher.update(&buf[0..n]),
+            Err(e) => {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                } else {
+                    return Err(e);
+                }
+            }
+        };
+    }
+    Ok(hasher.digest().bytes())
+}
+
+async fn existing_file_sha1_async(path: &Path) -> io::Result<Option<[u8; 20]>> {
+    match sha1_of_file_async(path).await {
+        Ok(o) => Ok(Some(o)),
+        Err(e) => {
+            if e.kind() == io::ErrorKind::NotFound {
+                Ok(None)
+            } else {
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Async counterpart to [add_data_to_folder_uniquely], preserving the same
+/// "same hash -> no-op, different hash -> hash-suffixed name" contract.
+pub(super) async fn add_data_to_folder_uniquely_async<'a>(
+    folder: &Path,
+    desired_name: &'a str,
+    data: &[u8],
+    sha1: [u8; 20],
+) -> Result<Cow<'a, str>> {
+    check_media_size_limit(desired_name, data)?;
+
+    let normalized_name = normalize_filename_with_data(desired_name, data);
+    let mut target_path = folder.join(normalized_name.as_ref());
+
+    let existing_file_hash = existing_file_sha1_async(&target_path).await?;
+    if existing_file_hash.is_none() {
+        // no file with that name exists yet
+        tokio::fs::write(&target_path, data).await?;
+        return Ok(normalized_name);
+    }
+
+    if existing_file_hash.unwrap() == sha1 {
+        // existing file has same checksum, nothing to do
+        return Ok(normalized_name);
+    }
+
+    // give it a unique name based on its hash
+    let hashed_name = add_hash_suffix_to_file_stem(normalized_name.as_ref(), &sha1);
+    target_path.set_file_name(&hashed_name);
+
+    tokio::fs::write(&target_path, data).await?;
+    Ok(hashed_name.into())
+}
+
+/// Async counterpart to [add_file_from_ankiweb].
+pub(super) async fn add_file_from_ankiweb_async(
+    media_folder: &Path,
+    fname: &str,
+    data: &[u8],
+) -> Result<AddedFile> {
+    check_media_size_limit(fname, data)?;
+
+    let sha1 = sha1_of_data(data);
+    let normalized = normalize_filename_with_data(fname, data);
+
+    // if the filename is already valid, we can write the file directly
+    let (renamed_from, path) = if let Cow::Borrowed(_) = normalized {
+        let path = media_folder.join(normalized.as_ref());
+        tokio::fs::write(&path, data).await?;
+        (None, path)
+    } else {
+        debug!("non-normalized filename received {}", fname);
+        // ankiweb sent us a non-normalized filename, so we'll rename it
+        let new_name = add_data_to_folder_uniquely_async(media_folder, fname, data, sha1).await?;
+        (
+            Some(new_name.to_string()),
+            media_folder.join(new_name.as_ref()),
+        )
+    };
+
+    let mtime = mtime_as_i64(&path)?;
+
+    Ok(AddedFile {
+        fname: normalized.to_string(),
+        sha1,
+        mtime,
+        renamed_from,
+    })
+}
+
+/// Async counterpart to [data_for_file].
+pub(super) async fn data_for_file_async(
+    media_folder: &Path,
+    fname: &str,
+) -> Result<Option<Vec<u8>>> {
+    match tokio::fs::read(media_folder.join(fname)).await {
+        Ok(data) => Ok(Some(data)),
+        Err(e) => {
+            if e.kind() == io::ErrorKind::NotFound {
+                Ok(None)
+            } else {
+                Err(e.into())
+            }
+        }
+    }
+}
+
+/// Write a batch of files downloaded from AnkiWeb concurrently, capping how
+/// many are in flight at once so a large batch doesn't exhaust file
+/// descriptors.
+pub(super) async fn add_files_from_ankiweb_concurrently(
+    media_folder: &Path,
+    files: Vec<(String, Vec<u8>)>,
+    max_concurrent: usize,
+) -> Result<Vec<AddedFile>> {
+    let media_folder: Arc<Path> = Arc::from(media_folder);
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(max_concurrent));
+    let mut tasks = Vec::with_capacity(files.len());
+
+    for (fname, data) in files {
+        let permit = semaphore.clone().acquire_owned().await.unwrap();
+        let folder = media_folder.clone();
+        tasks.push(tokio::spawn(async move {
+            let _permit = permit;
+            add_file_from_ankiweb_async(&folder, &fname, &data).await
+        }));
+    }
+
+    let mut added = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        added.push(task.await.expect("task panicked")?);
+    }
+    Ok(added)
+}
+
 #[cfg(test)]
 mod test {
+    use crate::err::AnkiError;
     use crate::media::files::{
-        add_data_to_folder_uniquely, add_hash_suffix_to_file_stem, normalize_filename,
-        remove_files, sha1_of_data, MAX_FILENAME_LENGTH,
+        add_data_to_folder_uniquely, add_data_to_folder_uniquely_async,
+        add_hash_suffix_to_file_stem, check_media_integrity, deduplicate_media,
+        export_media_to_tar, files_exceeding_sync_limit, find_duplicate_media,
+        import_media_from_tar, normalize_filename, normalize_filename_with_data, remove_files,
+        sha1_of_data, MediaKind, MediaProblem, MAX_FILENAME_LENGTH, MAX_TAR_ENTRY_SIZE,
+        MEDIA_SYNC_FILESIZE_LIMIT,
     };
     use std::borrow::Cow;
+    use std::io::Cursor;
     use tempfile::tempdir;
 
     #[test]
@@ -385,4 +1142,235 @@ mod test {
         // remove
         remove_files(dpath, written_files.as_slice()).unwrap();
     }
+
+    #[test]
+    fn tar_export_import_roundtrip() {
+        let src_dir = tempdir().unwrap();
+        std::fs::write(src_dir.path().join("foo.mp3"), "hello").unwrap();
+        std::fs::write(
+            src_dir.path().join("x".repeat(150) + ".jpg"),
+            "long name contents",
+        )
+        .unwrap();
+
+        let mut archive = Vec::new();
+        export_media_to_tar(src_dir.path(), &mut archive).unwrap();
+
+        let dst_dir = tempdir().unwrap();
+        import_media_from_tar(dst_dir.path(), &mut Cursor::new(archive)).unwrap();
+
+        let mut restored = std::fs::read_dir(dst_dir.path())
+            .unwrap()
+            .map(|d| d.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        restored.sort();
+        assert_eq!(restored.len(), 2);
+        assert_eq!(
+            std::fs::read(dst_dir.path().join("foo.mp3")).unwrap(),
+            b"hello"
+        );
+    }
+
+    #[test]
+    fn tar_import_restores_files_over_the_sync_limit() {
+        let src_dir = tempdir().unwrap();
+        std::fs::write(
+            src_dir.path().join("big.bin"),
+            vec![0u8; MEDIA_SYNC_FILESIZE_LIMIT + 1],
+        )
+        .unwrap();
+        std::fs::write(src_dir.path().join("small.mp3"), "hello").unwrap();
+
+        let mut archive = Vec::new();
+        export_media_to_tar(src_dir.path(), &mut archive).unwrap();
+
+        let dst_dir = tempdir().unwrap();
+        import_media_from_tar(dst_dir.path(), &mut Cursor::new(archive)).unwrap();
+
+        let mut restored = std::fs::read_dir(dst_dir.path())
+            .unwrap()
+            .map(|d| d.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        restored.sort();
+        assert_eq!(restored, vec!["big.bin".to_string(), "small.mp3".to_string()]);
+    }
+
+    #[test]
+    fn tar_import_rejects_absurd_entry_size() {
+        // a header whose declared size is beyond our sanity cap; no actual
+        // file data follows, so a naive reader would allocate first and only
+        // fail (or hang) trying to read bytes that were never written
+        let mut header = [0u8; 512];
+        header[0] = b'f';
+        let size = format!("{:011o}", MAX_TAR_ENTRY_SIZE + 1);
+        header[124..135].copy_from_slice(size.as_bytes());
+        header[156] = b'0';
+
+        let dir = tempdir().unwrap();
+        let err =
+            import_media_from_tar(dir.path(), &mut Cursor::new(header.to_vec())).unwrap_err();
+        assert!(matches!(err, AnkiError::IOError { .. }));
+    }
+
+    #[test]
+    fn duplicate_detection_and_dedup() {
+        let dir = tempdir().unwrap();
+        let dpath = dir.path();
+
+        std::fs::write(dpath.join("a.jpg"), "dupe").unwrap();
+        std::fs::write(dpath.join("b.jpg"), "dupe").unwrap();
+        std::fs::write(dpath.join("unique.jpg"), "alone").unwrap();
+
+        let mut clusters = find_duplicate_media(dpath).unwrap();
+        assert_eq!(clusters.len(), 1);
+        clusters[0].sort();
+        assert_eq!(clusters[0], vec!["a.jpg".to_string(), "b.jpg".to_string()]);
+
+        deduplicate_media(dpath).unwrap();
+        assert!(same_file::is_same_file(dpath.join("a.jpg"), dpath.join("b.jpg")).unwrap());
+    }
+
+    #[test]
+    fn dedup_with_preexisting_hardlinks() {
+        // b and d start out hardlinked to each other, but not to a; whichever
+        // file dedup picks as canonical, all three must end up as one file.
+        let dir = tempdir().unwrap();
+        let dpath = dir.path();
+
+        std::fs::write(dpath.join("a.jpg"), "dupe").unwrap();
+        std::fs::write(dpath.join("b.jpg"), "dupe").unwrap();
+        std::fs::hard_link(dpath.join("b.jpg"), dpath.join("d.jpg")).unwrap();
+
+        deduplicate_media(dpath).unwrap();
+
+        assert!(same_file::is_same_file(dpath.join("a.jpg"), dpath.join("b.jpg")).unwrap());
+        assert!(same_file::is_same_file(dpath.join("a.jpg"), dpath.join("d.jpg")).unwrap());
+    }
+
+    #[test]
+    fn integrity_check() {
+        let dir = tempdir().unwrap();
+        let dpath = dir.path();
+
+        // a valid, complete jpeg
+        std::fs::write(dpath.join("good.jpg"), [0xFF, 0xD8, 0xFF, 0, 0xFF, 0xD9]).unwrap();
+        // truncated before the jpeg trailer
+        std::fs::write(dpath.join("bad.jpg"), [0xFF, 0xD8, 0xFF, 0]).unwrap();
+        // a png saved with the wrong extension
+        std::fs::write(
+            dpath.join("mislabelled.jpg"),
+            [0x89, 0x50, 0x4E, 0x47, 0, 0, 0, 0, b'I', b'E', b'N', b'D'],
+        )
+        .unwrap();
+
+        let mut problems = check_media_integrity(dpath).unwrap();
+        problems.sort_by(|a, b| a.fname.cmp(&b.fname));
+
+        assert_eq!(problems.len(), 2);
+        assert_eq!(problems[0].fname, "bad.jpg");
+        assert_eq!(problems[0].detected_kind, MediaKind::Jpeg);
+        assert_eq!(problems[0].problem, MediaProblem::Truncated);
+        assert_eq!(problems[1].fname, "mislabelled.jpg");
+        assert_eq!(problems[1].detected_kind, MediaKind::Png);
+        assert_eq!(problems[1].problem, MediaProblem::MismatchedExtension);
+    }
+
+    #[test]
+    fn webm_truncation() {
+        let dir = tempdir().unwrap();
+        let dpath = dir.path();
+
+        // EBML header element: id (4 bytes) + size vint (1 byte, value 4) +
+        // a 4-byte payload, with nothing left over
+        let mut complete = vec![0x1A, 0x45, 0xDF, 0xA3, 0x84];
+        complete.extend_from_slice(&[0, 0, 0, 0]);
+        std::fs::write(dpath.join("good.webm"), &complete).unwrap();
+
+        // same header, but the payload is cut short
+        let truncated = &complete[..complete.len() - 2];
+        std::fs::write(dpath.join("bad.webm"), truncated).unwrap();
+
+        let mut problems = check_media_integrity(dpath).unwrap();
+        problems.sort_by(|a, b| a.fname.cmp(&b.fname));
+
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].fname, "bad.webm");
+        assert_eq!(problems[0].detected_kind, MediaKind::Webm);
+        assert_eq!(problems[0].problem, MediaProblem::Truncated);
+    }
+
+    #[test]
+    fn normalize_with_data() {
+        let png = [0x89, 0x50, 0x4E, 0x47, 0, 0, 0, 0];
+
+        // missing/wrong extension is corrected based on sniffed content
+        assert_eq!(
+            normalize_filename_with_data("image", &png).as_ref(),
+            "image.png"
+        );
+        assert_eq!(
+            normalize_filename_with_data("sound.dat", &png).as_ref(),
+            "sound.png"
+        );
+
+        // already-correct names take the Cow::Borrowed fast path
+        assert!(matches!(
+            normalize_filename_with_data("foo.png", &png),
+            Cow::Borrowed("foo.png")
+        ));
+
+        // unrecognized content is left alone
+        assert_eq!(
+            normalize_filename_with_data("foo.dat", b"not media"),
+            Cow::Borrowed("foo.dat")
+        );
+
+        // appending the sniffed extension must not push the name over
+        // MAX_FILENAME_LENGTH
+        let long_name = "x".repeat(MAX_FILENAME_LENGTH);
+        let result = normalize_filename_with_data(&long_name, &png);
+        assert!(result.len() <= MAX_FILENAME_LENGTH);
+        assert!(result.ends_with(".png"));
+    }
+
+    #[tokio::test]
+    async fn add_data_async() {
+        let dir = tempdir().unwrap();
+        let dpath = dir.path();
+
+        let h1 = sha1_of_data("hello".as_bytes());
+        assert_eq!(
+            add_data_to_folder_uniquely_async(dpath, "test.mp3", "hello".as_bytes(), h1)
+                .await
+                .unwrap(),
+            "test.mp3"
+        );
+
+        // same contents case is a no-op
+        assert_eq!(
+            add_data_to_folder_uniquely_async(dpath, "test.mp3", "hello".as_bytes(), h1)
+                .await
+                .unwrap(),
+            "test.mp3"
+        );
+    }
+
+    #[test]
+    fn media_size_limit() {
+        let dir = tempdir().unwrap();
+        let dpath = dir.path();
+
+        let oversized = vec![0u8; MEDIA_SYNC_FILESIZE_LIMIT + 1];
+        let sha1 = sha1_of_data(&oversized);
+        let err = add_data_to_folder_uniquely(dpath, "big.mp3", &oversized, sha1).unwrap_err();
+        assert!(matches!(err, AnkiError::MediaTooLarge { .. }));
+
+        // a file already on disk over the limit is reported by the audit routine
+        let file = std::fs::File::create(dpath.join("already_big.mp3")).unwrap();
+        file.set_len((MEDIA_SYNC_FILESIZE_LIMIT + 1) as u64).unwrap();
+
+        let found = files_exceeding_sync_limit(dpath).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].fname, "already_big.mp3");
+    }
 }